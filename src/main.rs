@@ -4,15 +4,38 @@
 /// Interpolate stdin with environment variables or variable definitions
 /// from a file and send to stdout
 ///
-/// Use placeholder guards "${" and "}" around variable names that will be
-/// looked up in either environment variables or a definitions file. Keys are
-/// always *case-insensitive*.
+/// Use placeholder guards "${" and "}", or "$(" and ")", around variable names
+/// that will be looked up in either environment variables or a definitions
+/// file. Keys are always *case-insensitive*.
 ///
 /// e.g. city = CITY = cItY
 ///
+/// With --bare, a bare "$NAME" (no guards) is also recognized, provided NAME
+/// is a valid identifier. This is opt-in since it's easy for a plain "$" in
+/// an input to collide with it.
+///
+/// Shell-style operators are recognized inside a placeholder body:
+/// "${KEY:-default}" substitutes "default" when KEY is unset or empty
+/// "${KEY-default}"  substitutes "default" only when KEY is entirely unset
+/// "${KEY:?message}" aborts with "message" when KEY is unset or empty,
+///                   regardless of whether -e was passed
+///
+/// A key that is itself a known definition always wins over operator
+/// splitting, so a hyphenated key such as "${content-type}" round-trips
+/// correctly as long as "content-type" is defined somewhere. An *undefined*
+/// hyphenated placeholder like "${my-var}" (no "my-var" definition) still
+/// parses as key "my" with default "var" -- same as shell, where variable
+/// names never contain a "-" in the first place.
+///
 /// si will succeed except for a few cases:
-/// 1. placeholder guards are mismatched or incorrect
-/// 2. -e (error) flag has been specified but a variable defintion was not found
+/// 1. -e (error) flag has been specified but a variable defintion was not found
+/// 2. a placeholder's "${KEY:?message}" operator fires, or a recursively
+///    resolved definition is cyclic or too deeply nested -- both abort
+///    regardless of -e
+///
+/// A placeholder with mismatched or incorrect guards (e.g. an unclosed
+/// "${name") is not an error: it simply isn't recognized as a placeholder,
+/// so the text passes through to stdout untouched.
 ///
 /// File Formatting:
 /// text files use the convention of a variable name, an equals sign (=), and a variables
@@ -44,11 +67,30 @@
 /// "${ocean.creature}" will resolve to "whale"
 /// 
 ///
-/// Usage: 
-/// $ si [-v] [-e] [-f <variables file>]
+/// Environment variables are always part of the mapping, layered against any
+/// `-f` files given. `-f` may be repeated; later files override earlier
+/// ones. By default environment variables take precedence over every file
+/// (twelve-factor style); pass --env-first to make them the lowest
+/// precedence instead, so files override them.
+///
+/// Definitions may themselves reference other definitions, e.g.
+/// `url=https://${host}:${port}` -- these are resolved lazily, on demand, as
+/// stdin actually references them, so an unrelated definition (an unrelated
+/// `-f` entry, or any of the many environment variables folded into the
+/// mapping) that happens to contain "${...}"-looking text never affects a
+/// run that doesn't reference it. A definition that (directly or
+/// transitively) references itself is an error, but again only once
+/// something in stdin actually reaches it. Pass --no-recursive to treat
+/// "${...}" in a definition's value as a literal instead.
+///
+/// Usage:
+/// $ si [-v] [-e] [-f <variables file>]... [--bare] [--env-first] [--no-recursive]
 /// -v : acknowledge found and not found variables to stderr
 /// -e : terminate with error if a variable is not found
-/// -f <file> : specify a file with variable defintions (text or json)
+/// -f <file> : specify a file with variable defintions (text or json); repeatable
+/// --bare : also recognize bare $NAME placeholders
+/// --env-first : environment variables are overridden by -f files instead of overriding them
+/// --no-recursive : don't resolve placeholders inside variable definitions
 ///
 /// use environment variables
 /// $ cat raw.txt | si > processed.txt
@@ -57,52 +99,398 @@
 /// $ cat raw.txt | si -f defs.json > processed.txt
 /// $ cat raw.txt | si -f defs.txt > processed.txt
 ///
+/// layer a base file, a per-stage override file, and let the environment win
+/// $ cat raw.txt | si -f defs.json -f overrides.txt > processed.txt
+///
 /// use stdin and stdout
 /// $ echo "hello ${name}" | si > processed.txt
 
-use std::{io::{self, Read, Write}, collections::HashMap, path::Path};
+use std::{fs, io::{self, Read, Write}, collections::HashMap, path::Path, process};
 
 use clap::{Arg, App};
+use regex::Regex;
+use serde_json::Value;
+
+/// Matches `${key}` and `$(key)` placeholders, capturing the key.
+const PLACEHOLDER: &str = r"\$\{(?P<key_brace>[^}]+)\}|\$\((?P<key_paren>[^)]+)\)";
+
+/// Matches a bare `$key` placeholder. Opt-in via `--bare`, tried only after
+/// the brace and paren forms above so `${` / `$(` are never also matched here.
+const PLACEHOLDER_BARE: &str = r"\$\{(?P<key_brace>[^}]+)\}|\$\((?P<key_paren>[^)]+)\)|\$(?P<key_bare>[a-zA-Z_][a-zA-Z0-9_]*)";
+
 enum Mode {
     TextFile(String),
     JsonFile(String),
     Env
 }
 
-fn parse(mode: Mode) -> HashMap<String, String> {
+fn parse_one(mode: Mode) -> HashMap<String, String> {
+    match mode {
+        Mode::TextFile(path) => parse_text_file(&path),
+        Mode::JsonFile(path) => parse_json_file(&path),
+        Mode::Env => std::env::vars()
+            .map(|(k, v)| (k.to_ascii_lowercase(), v))
+            .collect(),
+    }
+}
+
+/// Parse every source in `modes` and fold them into a single mapping. Sources
+/// are applied in order, so a key defined by a later mode overrides the same
+/// key from an earlier one -- this is how `-f` layering and the env overlay
+/// get their precedence.
+fn parse(modes: Vec<Mode>) -> HashMap<String, String> {
+    let mut mapping = HashMap::new();
+    for mode in modes {
+        mapping.extend(parse_one(mode));
+    }
+    mapping
+}
+
+/// Parse a text definitions file: one `key=value` pair per line. A doubled
+/// `==` is an escape for a literal `=` within the key or value, so
+/// `equation=y == mx + b` yields key `equation`, value `y = mx + b`.
+fn parse_text_file(path: &str) -> HashMap<String, String> {
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Unable to read \"{}\": {}", path, e));
+
     let mut mapping = HashMap::new();
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
 
-    match mode {
-        Mode::TextFile(_) => todo!(),
-        Mode::JsonFile(_) => todo!(),
-        Mode::Env => {
-            mapping = std::env::vars().collect();
-        },
+        // collapse the "==" escape into a literal "=" before splitting, then
+        // split on the first remaining "=" to find the key/value boundary
+        let placeholder = "\0";
+        let escaped = line.replace("==", placeholder);
+        let (key, value) = match escaped.split_once('=') {
+            Some((k, v)) => (k, v),
+            None => panic!("Malformed line {} in \"{}\": missing \"=\"", lineno + 1, path),
+        };
+
+        let key = key.replace(placeholder, "=").trim().to_ascii_lowercase();
+        let value = value.replace(placeholder, "=").trim().to_string();
+        mapping.insert(key, value);
     }
 
-    return mapping;
+    mapping
+}
+
+/// Parse a JSON definitions file, flattening nested objects into dot-joined
+/// keys, e.g. `{"ocean":{"creature":"whale"}}` becomes `ocean.creature -> whale`.
+fn parse_json_file(path: &str) -> HashMap<String, String> {
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Unable to read \"{}\": {}", path, e));
+    let root: Value = serde_json::from_str(&contents)
+        .unwrap_or_else(|e| panic!("Malformed JSON in \"{}\": {}", path, e));
+
+    let mut mapping = HashMap::new();
+    flatten_json(path, "", &root, &mut mapping);
+    mapping
+}
+
+fn flatten_json(path: &str, prefix: &str, value: &Value, mapping: &mut HashMap<String, String>) {
+    match value {
+        Value::Object(obj) => {
+            for (key, value) in obj.iter() {
+                let joined = if prefix.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_json(path, &joined, value, mapping);
+            }
+        }
+        Value::String(s) => {
+            mapping.insert(prefix.to_ascii_lowercase(), s.to_string());
+        }
+        _ => panic!("Non-string value at \"{}\" in \"{}\"", prefix, path),
+    }
 }
 
-fn interpolate(verbose: bool, strict: bool, mapping: HashMap<String, String>) {
+/// Shell-style parameter-expansion operator found inside a placeholder body,
+/// e.g. the `:-default` part of `${KEY:-default}`.
+enum Operator<'a> {
+    None,
+    /// `${KEY-default}`: substitute `default` only when KEY is entirely unset.
+    DefaultIfUnset(&'a str),
+    /// `${KEY:-default}`: substitute `default` when KEY is unset or empty.
+    DefaultIfUnsetOrEmpty(&'a str),
+    /// `${KEY:?message}`: abort with `message` when KEY is unset or empty.
+    RequiredElse(&'a str),
+}
+
+/// Split a placeholder body into its key and operator.
+///
+/// Before scanning for an operator, `known` is consulted with the body
+/// as-is: if it names an already-defined key, the body is returned whole
+/// with `Operator::None`, so a literal hyphenated key (e.g. `content-type`)
+/// isn't mistaken for `content` with a default of `type`.
+///
+/// Otherwise an explicit `:-` or `:?` is searched for first, wherever it
+/// appears -- this always wins over a bare `-`, so a hyphenated key paired
+/// with an explicit operator (e.g. `content-type:-fallback`) splits on the
+/// operator rather than on the hyphen inside the key name. Only when no
+/// explicit operator is present does the first bare `-` get treated as
+/// `Operator::DefaultIfUnset`.
+fn split_operator<'a>(body: &'a str, known: &dyn Fn(&str) -> bool) -> (&'a str, Operator<'a>) {
+    if known(body) {
+        return (body, Operator::None);
+    }
+
+    let bytes = body.as_bytes();
+    for i in 0..bytes.len() {
+        if bytes[i] == b':' {
+            match bytes.get(i + 1) {
+                Some(b'-') => return (&body[..i], Operator::DefaultIfUnsetOrEmpty(&body[i + 2..])),
+                Some(b'?') => return (&body[..i], Operator::RequiredElse(&body[i + 2..])),
+                _ => (),
+            }
+        }
+    }
+    for i in 0..bytes.len() {
+        if bytes[i] == b'-' {
+            return (&body[..i], Operator::DefaultIfUnset(&body[i + 1..]));
+        }
+    }
+    (body, Operator::None)
+}
+
+/// Safety valve for `resolve_key`: the deepest chain of definitions
+/// referencing each other that will be followed before giving up. Genuine
+/// cycles are caught well before this by `visiting`; this just bounds
+/// otherwise-valid but absurdly deep chains.
+const MAX_RESOLUTION_DEPTH: usize = 32;
+
+/// A failure while expanding placeholders inside variable definitions.
+/// Kept separate from printing/exiting so `resolve_key` stays unit-testable;
+/// `interpolate` is the only caller that turns one of these into a
+/// process exit.
+#[derive(Debug)]
+enum ResolveError {
+    /// `chain` is `visiting` with the cycle's repeated key appended.
+    Cyclic(Vec<String>),
+    /// the definition chain starting at this key exceeded `MAX_RESOLUTION_DEPTH`.
+    TooDeep(String),
+    /// a `${KEY:?message}` placeholder's KEY was unset or empty, which aborts
+    /// regardless of the global strict (`-e`) flag.
+    Required(String),
+}
+
+impl ResolveError {
+    fn report_and_exit(self) -> ! {
+        match self {
+            ResolveError::Cyclic(chain) => {
+                eprintln!("si: cyclic definition detected: {}", chain.join(" -> "));
+            }
+            ResolveError::TooDeep(key) => {
+                eprintln!("si: definition chain for \"{}\" exceeds {} levels", key, MAX_RESOLUTION_DEPTH);
+            }
+            ResolveError::Required(message) => eprintln!("{}", message),
+        }
+        process::exit(1);
+    }
+}
+
+/// Expand placeholders within `raw[key]`'s value, resolving references to
+/// other keys in `raw` recursively so that e.g. `url=https://${host}:${port}`
+/// picks up `host` and `port`'s own (possibly further-nested) values.
+/// `visiting` is the chain of keys currently being expanded, used to detect
+/// `A` referencing `B` referencing `A`. Operators inside a reference (e.g.
+/// the `:?` in `${host:?message}`) are applied the same way `interpolate`
+/// applies them -- a reference that's merely absent isn't an error unless
+/// its operator says so.
+fn resolve_key(
+    key: &str,
+    raw: &HashMap<String, String>,
+    resolved: &mut HashMap<String, String>,
+    visiting: &mut Vec<String>,
+    placeholder: &Regex,
+) -> Result<String, ResolveError> {
+    if let Some(cached) = resolved.get(key) {
+        return Ok(cached.clone());
+    }
+
+    if visiting.iter().any(|k| k == key) {
+        visiting.push(key.to_string());
+        return Err(ResolveError::Cyclic(visiting.clone()));
+    }
+    if visiting.len() >= MAX_RESOLUTION_DEPTH {
+        return Err(ResolveError::TooDeep(key.to_string()));
+    }
+
+    let raw_value = raw.get(key).cloned().unwrap_or_default();
+    visiting.push(key.to_string());
+
+    let mut out = String::new();
+    let mut last = 0;
+    for caps in placeholder.captures_iter(&raw_value) {
+        let whole = caps.get(0).unwrap();
+        out.push_str(&raw_value[last..whole.start()]);
+
+        let body = caps.name("key_brace")
+            .or_else(|| caps.name("key_paren"))
+            .or_else(|| caps.name("key_bare"))
+            .expect("placeholder matched without a captured key")
+            .as_str();
+        let (refkey, op) = split_operator(body, &|k| raw.contains_key(&k.trim().to_ascii_lowercase()));
+        let refkey = refkey.trim().to_ascii_lowercase();
+
+        let found = if raw.contains_key(&refkey) {
+            Some(resolve_key(&refkey, raw, resolved, visiting, placeholder)?)
+        } else {
+            None
+        };
+
+        match op {
+            Operator::None => match found {
+                Some(value) => out.push_str(&value),
+                None => out.push_str(whole.as_str()),
+            },
+            Operator::DefaultIfUnsetOrEmpty(default) => match &found {
+                Some(value) if !value.is_empty() => out.push_str(value),
+                _ => out.push_str(default),
+            },
+            Operator::DefaultIfUnset(default) => match &found {
+                Some(value) => out.push_str(value),
+                None => out.push_str(default),
+            },
+            Operator::RequiredElse(message) => match &found {
+                Some(value) if !value.is_empty() => out.push_str(value),
+                _ => return Err(ResolveError::Required(message.to_string())),
+            },
+        }
+
+        last = whole.end();
+    }
+    out.push_str(&raw_value[last..]);
+
+    visiting.pop();
+    resolved.insert(key.to_string(), out.clone());
+    Ok(out)
+}
+
+fn interpolate(verbose: bool, strict: bool, bare: bool, recursive: bool, mapping: HashMap<String, String>) {
     let mut buf = String::new();
     match io::stdin().read_to_string(&mut buf) {
         Ok(_sz) => (),
         Err(_) => panic!("No input provided"),
     };
 
-    for (key, value) in mapping.iter() {
-        let buf = buf.replace(format!("${{{}}}", key).as_str(), value);
-    }
+    let pattern = if bare { PLACEHOLDER_BARE } else { PLACEHOLDER };
+    let placeholder = Regex::new(pattern).expect("invalid placeholder regex");
+
+    // Definitions that reference other definitions (e.g. a file defining
+    // `url=https://${host}:${port}`) are resolved lazily, through this cache,
+    // only for keys stdin actually references -- not eagerly for the whole
+    // mapping, which would mean an unrelated definition (e.g. an unrelated
+    // environment variable) could abort a run it has nothing to do with.
+    let mut resolved = HashMap::new();
 
-    // TODO: strict
-    // TODO: verbose
+    let out = placeholder.replace_all(&buf, |caps: &regex::Captures| {
+        let body = caps.name("key_brace")
+            .or_else(|| caps.name("key_paren"))
+            .or_else(|| caps.name("key_bare"))
+            .expect("placeholder matched without a captured key")
+            .as_str();
+        let (key, op) = split_operator(body, &|k| mapping.contains_key(&k.trim().to_ascii_lowercase()));
+        let key = key.trim();
+        let lookup_key = key.to_ascii_lowercase();
 
-    match io::stdout().lock().write(buf.as_bytes()) {
+        let found = if !mapping.contains_key(&lookup_key) {
+            None
+        } else if recursive {
+            let mut visiting = Vec::new();
+            match resolve_key(&lookup_key, &mapping, &mut resolved, &mut visiting, &placeholder) {
+                Ok(value) => Some(value),
+                Err(e) => e.report_and_exit(),
+            }
+        } else {
+            mapping.get(&lookup_key).cloned()
+        };
+
+        match op {
+            Operator::None => match found {
+                Some(value) => {
+                    if verbose {
+                        eprintln!("found {}", key);
+                    }
+                    value
+                }
+                None => {
+                    if verbose {
+                        eprintln!("not found {}", key);
+                    }
+                    if strict {
+                        eprintln!("si: no definition for \"{}\"", key);
+                        process::exit(1);
+                    }
+                    caps[0].to_string()
+                }
+            },
+            Operator::DefaultIfUnsetOrEmpty(default) => match found {
+                Some(value) if !value.is_empty() => {
+                    if verbose {
+                        eprintln!("found {}", key);
+                    }
+                    value
+                }
+                _ => {
+                    if verbose {
+                        eprintln!("not found {}, using default", key);
+                    }
+                    default.to_string()
+                }
+            },
+            Operator::DefaultIfUnset(default) => match found {
+                Some(value) => {
+                    if verbose {
+                        eprintln!("found {}", key);
+                    }
+                    value
+                }
+                None => {
+                    if verbose {
+                        eprintln!("not found {}, using default", key);
+                    }
+                    default.to_string()
+                }
+            },
+            Operator::RequiredElse(message) => match found {
+                Some(value) if !value.is_empty() => {
+                    if verbose {
+                        eprintln!("found {}", key);
+                    }
+                    value
+                }
+                _ => {
+                    eprintln!("{}", message);
+                    process::exit(1);
+                }
+            },
+        }
+    });
+
+    match io::stdout().lock().write(out.as_bytes()) {
         Ok(_sz) => (),
         Err(e) => panic!("{}", e),
     }
 }
 
+/// Determine the `Mode` for a `-f` path from its extension.
+fn mode_for_path(p: &str) -> Mode {
+    match Path::new(p).extension() {
+        Some(s) => match s.to_ascii_lowercase().to_str() {
+            Some("txt") => Mode::TextFile(p.to_string()),
+            Some("json") => Mode::JsonFile(p.to_string()),
+            Some(_) => panic!("Only text (\"txt\") and JSON (\"json\") files are supported"),
+            None => panic!("Path is not UTF-8 encoded")
+        }
+        None => panic!("Unable to detect file extension"),
+    }
+}
+
 fn main() {
     let matches = App::new("si")
         .version("1.0")
@@ -115,29 +503,197 @@ fn main() {
             .short("f")
             .long("file")
             .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
             .value_name("FILE")
-            .help("text or json file to process with variable definitions"))
+            .help("text or json file to process with variable definitions; may be repeated, later files override earlier ones"))
         .arg(Arg::with_name("error")
             .short("e")
             .long("error")
             .help("terminate on not found results"))
+        .arg(Arg::with_name("bare")
+            .long("bare")
+            .help("also recognize bare $VAR placeholders, in addition to ${VAR} and $(VAR)"))
+        .arg(Arg::with_name("env-first")
+            .long("env-first")
+            .help("give environment variables the lowest precedence instead of the highest (default: env overlay wins over all -f files)"))
+        .arg(Arg::with_name("no-recursive")
+            .long("no-recursive")
+            .help("don't resolve placeholders found inside variable definitions themselves"))
         .get_matches();
-    
+
     let strict = matches.is_present("error");
     let verbose = matches.is_present("verbose");
-    let mode: Mode = match matches.value_of("file") {
-        Some(p) => match Path::new(p).extension() {
-            Some(s) => match s.to_ascii_lowercase().to_str() {
-                Some("txt") => Mode::TextFile(s.to_string_lossy().to_string()),
-                Some("json") => Mode::JsonFile(s.to_string_lossy().to_string()),
-                Some(_) => panic!("Only text (\"txt\") and JSON (\"json\") files are supported"),
-                None => panic!("Path is not UTF-8 encoded")
-            }
-            None => panic!("Unable to detect file extension"),
-        }
-        None => Mode::Env
+    let bare = matches.is_present("bare");
+    let env_first = matches.is_present("env-first");
+    let no_recursive = matches.is_present("no-recursive");
+
+    let file_modes: Vec<Mode> = match matches.values_of("file") {
+        Some(paths) => paths.map(mode_for_path).collect(),
+        None => Vec::new(),
     };
 
-    let mapping = parse(mode);
-    interpolate(verbose, strict, mapping);
+    let modes = if env_first {
+        std::iter::once(Mode::Env).chain(file_modes).collect()
+    } else {
+        file_modes.into_iter().chain(std::iter::once(Mode::Env)).collect()
+    };
+
+    let mapping = parse(modes);
+    interpolate(verbose, strict, bare, !no_recursive, mapping);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_operator_known_hyphenated_key_wins_over_default_op() {
+        let known = |k: &str| k == "content-type";
+        let (key, op) = split_operator("content-type", &known);
+        assert_eq!(key, "content-type");
+        assert!(matches!(op, Operator::None));
+    }
+
+    #[test]
+    fn split_operator_unknown_hyphenated_key_falls_back_to_default_op() {
+        let known = |_: &str| false;
+        let (key, op) = split_operator("my-var", &known);
+        assert_eq!(key, "my");
+        match op {
+            Operator::DefaultIfUnset(default) => assert_eq!(default, "var"),
+            _ => panic!("expected DefaultIfUnset"),
+        }
+    }
+
+    #[test]
+    fn split_operator_explicit_operator_wins_over_hyphen_in_key() {
+        // "content-type" isn't itself a known key here (only the combined
+        // body is being parsed), so the hyphen inside it must not be
+        // mistaken for the default operator ahead of the real ":-".
+        let known = |_: &str| false;
+        let (key, op) = split_operator("content-type:-fallback", &known);
+        assert_eq!(key, "content-type");
+        match op {
+            Operator::DefaultIfUnsetOrEmpty(default) => assert_eq!(default, "fallback"),
+            _ => panic!("expected DefaultIfUnsetOrEmpty"),
+        }
+    }
+
+    fn brace_placeholder() -> Regex {
+        Regex::new(PLACEHOLDER).expect("invalid placeholder regex")
+    }
+
+    #[test]
+    fn resolve_key_detects_direct_cycle() {
+        let mut raw = HashMap::new();
+        raw.insert("a".to_string(), "${a}".to_string());
+        let mut resolved = HashMap::new();
+        let mut visiting = Vec::new();
+        let placeholder = brace_placeholder();
+
+        let err = resolve_key("a", &raw, &mut resolved, &mut visiting, &placeholder)
+            .expect_err("expected cyclic definition to be rejected");
+        assert!(matches!(err, ResolveError::Cyclic(_)));
+    }
+
+    #[test]
+    fn resolve_key_resolves_diamond_shaped_definitions() {
+        // a depends on b and c, which both depend on d -- not a cycle, just
+        // shared ancestry, and should resolve cleanly with d substituted twice.
+        let mut raw = HashMap::new();
+        raw.insert("a".to_string(), "${b}-${c}".to_string());
+        raw.insert("b".to_string(), "${d}/b".to_string());
+        raw.insert("c".to_string(), "${d}/c".to_string());
+        raw.insert("d".to_string(), "root".to_string());
+        let mut resolved = HashMap::new();
+        let placeholder = brace_placeholder();
+
+        let mut visiting = Vec::new();
+        let value = resolve_key("a", &raw, &mut resolved, &mut visiting, &placeholder)
+            .expect("diamond-shaped definitions should not be treated as cyclic");
+        assert_eq!(value, "root/b-root/c");
+    }
+
+    #[test]
+    fn resolve_key_applies_default_operator_on_nested_reference() {
+        let mut raw = HashMap::new();
+        raw.insert("url".to_string(), "https://${missing:-fallback}".to_string());
+        let mut resolved = HashMap::new();
+        let mut visiting = Vec::new();
+        let placeholder = brace_placeholder();
+
+        let value = resolve_key("url", &raw, &mut resolved, &mut visiting, &placeholder)
+            .expect("missing key with a default should resolve, not error");
+        assert_eq!(value, "https://fallback");
+    }
+
+    #[test]
+    fn resolve_key_applies_required_operator_on_nested_reference() {
+        let mut raw = HashMap::new();
+        raw.insert("host".to_string(), "${missing:?host is required}".to_string());
+        raw.insert("url".to_string(), "https://${host}".to_string());
+        let mut resolved = HashMap::new();
+        let placeholder = brace_placeholder();
+
+        let mut visiting = Vec::new();
+        let err = resolve_key("url", &raw, &mut resolved, &mut visiting, &placeholder)
+            .expect_err("a required-but-missing nested key must abort, not pass through literally");
+        match err {
+            ResolveError::Required(message) => assert_eq!(message, "host is required"),
+            _ => panic!("expected ResolveError::Required"),
+        }
+    }
+
+    // parse_text_file/parse_json_file read real files, so these write a
+    // scratch file under the OS temp dir, named after the test so concurrent
+    // `cargo test` runs don't collide.
+    fn write_temp_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("si_test_{}_{:?}.tmp", name, std::thread::current().id()));
+        fs::write(&path, contents).expect("failed to write scratch file");
+        path.to_str().expect("scratch path is not UTF-8").to_string()
+    }
+
+    #[test]
+    fn parse_text_file_round_trips_escaped_equals() {
+        let path = write_temp_file("escaped_equals", "equation=y == mx + b\n");
+        let mapping = parse_text_file(&path);
+        fs::remove_file(&path).ok();
+
+        assert_eq!(mapping.get("equation").map(String::as_str), Some("y = mx + b"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Malformed line")]
+    fn parse_text_file_panics_on_malformed_line() {
+        let path = write_temp_file("malformed_line", "no_equals_sign_here\n");
+        parse_text_file(&path);
+    }
+
+    #[test]
+    fn parse_text_file_stores_keys_case_insensitively() {
+        let path = write_temp_file("case_insensitive", "HOST=example.com\n");
+        let mapping = parse_text_file(&path);
+        fs::remove_file(&path).ok();
+
+        assert_eq!(mapping.get("host").map(String::as_str), Some("example.com"));
+        assert!(!mapping.contains_key("HOST"));
+    }
+
+    #[test]
+    fn flatten_json_joins_nested_object_keys_with_dots() {
+        let value: Value = serde_json::from_str(r#"{"ocean":{"creature":"whale"}}"#).unwrap();
+        let mut mapping = HashMap::new();
+        flatten_json("test.json", "", &value, &mut mapping);
+
+        assert_eq!(mapping.get("ocean.creature").map(String::as_str), Some("whale"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Non-string value")]
+    fn flatten_json_panics_on_non_string_leaf() {
+        let value: Value = serde_json::from_str(r#"{"count":3}"#).unwrap();
+        let mut mapping = HashMap::new();
+        flatten_json("test.json", "", &value, &mut mapping);
+    }
 }